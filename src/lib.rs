@@ -0,0 +1,447 @@
+//! Core mod-packaging logic for rfmp, usable without going through the CLI.
+//!
+//! The [`ModPackager`] builder walks a mod's source directory, assembles a
+//! `name_version.zip` archive the way Factorio expects it, and can either hand the
+//! archive to an arbitrary [`Write`]r, write it to a path, or install it straight
+//! into a Factorio `mods` directory.
+
+use std::{
+    ffi::OsStr,
+    fmt::Display,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+use glob::glob;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use mtzip::{level::CompressionLevel, CompressionType, ZipArchive};
+use rayon::ThreadPoolBuilder;
+use serde::Deserialize;
+use serde_json::from_reader;
+use thiserror::Error;
+use walkdir::{DirEntry, WalkDir};
+
+/// Largest single-file size or archive total that fits in a classic (non-ZIP64) local/central
+/// header field.
+const CLASSIC_ZIP_SIZE_LIMIT: u64 = u32::MAX as u64;
+/// Largest number of entries a classic end-of-central-directory record can index.
+const CLASSIC_ZIP_ENTRY_LIMIT: usize = u16::MAX as usize;
+
+/// Everything that can go wrong while packaging or installing a mod.
+#[derive(Debug, Error)]
+pub enum RfmpError {
+    #[error("failed to open info.json in {0}: {1}")]
+    ReadInfoJson(PathBuf, #[source] std::io::Error),
+    #[error("failed to parse info.json: {0}")]
+    ParseInfoJson(#[source] serde_json::Error),
+    #[error("install directory {0} doesn't exist")]
+    InstallDirMissing(PathBuf),
+    #[error("failed to construct glob pattern: {0}")]
+    Glob(#[from] glob::PatternError),
+    #[error("failed to remove old version {0}: {1}")]
+    RemoveOldVersion(PathBuf, #[source] std::io::Error),
+    #[error("failed to parse exclude patterns: {0}")]
+    ExcludePattern(#[from] ignore::Error),
+    #[error(
+        "mod needs {0} archive entries, more than the {CLASSIC_ZIP_ENTRY_LIMIT} entries a classic zip \
+         central directory can index (ZIP64 isn't supported yet)"
+    )]
+    TooManyEntries(usize),
+    #[error(
+        "mod contents total {0} bytes, more than the {CLASSIC_ZIP_SIZE_LIMIT} byte limit of a \
+         classic zip (ZIP64 isn't supported yet)"
+    )]
+    ArchiveTooLarge(u64),
+    #[error("failed to add directory {0} to archive")]
+    AddDirectory(String),
+    #[error("failed to remove existing archive {0}: {1}")]
+    RemoveExisting(PathBuf, #[source] std::io::Error),
+    #[error("failed to open output file {0}: {1}")]
+    CreateOutputFile(PathBuf, #[source] std::io::Error),
+    #[error("failed to write archive: {0}")]
+    WriteArchive(#[source] std::io::Error),
+    #[error("failed to move completed archive into place at {0}: {1}")]
+    FinalizeOutputFile(PathBuf, #[source] std::io::Error),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InfoJson {
+    pub name: String,
+    pub version: String,
+}
+
+impl Display for InfoJson {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}_{}", self.name, self.version)
+    }
+}
+
+fn get_default_factorio_home() -> PathBuf {
+    if cfg!(target_os = "linux") {
+        dirs::home_dir().unwrap().join(".factorio/mods")
+    } else if cfg!(target_os = "windows") {
+        dirs::data_dir().unwrap().join("Factorio/mods")
+    } else {
+        println!("Warning: unknown OS. Please report to github what OS you use and where `mods` directory is located. Using current directory as a fallback");
+        PathBuf::from(".")
+    }
+}
+
+/// Open `info.json` under `source_dir` and parse it
+fn get_info_json(source_dir: &Path) -> Result<InfoJson, RfmpError> {
+    let info_path = source_dir.join("info.json");
+    let info_file =
+        File::open(&info_path).map_err(|e| RfmpError::ReadInfoJson(info_path.clone(), e))?;
+    from_reader(info_file).map_err(RfmpError::ParseInfoJson)
+}
+
+/// Mods directory path
+fn get_target_dir(install_dir: Option<PathBuf>) -> Result<PathBuf, RfmpError> {
+    let mods_target_dir = install_dir.unwrap_or_else(get_default_factorio_home);
+
+    if !mods_target_dir.exists() {
+        return Err(RfmpError::InstallDirMissing(mods_target_dir));
+    }
+
+    Ok(mods_target_dir)
+}
+
+fn make_glob_str(target_dir: &Path, mod_name: &str) -> String {
+    format!(
+        "{}/{}_*[0-9].*[0-9].*[0-9].zip",
+        target_dir.to_string_lossy(),
+        mod_name
+    )
+}
+
+fn remove_old_versions(target_dir: &Path, mod_name: &str) -> Result<(), RfmpError> {
+    let mod_glob_str = make_glob_str(target_dir, mod_name);
+    let mod_glob = glob(&mod_glob_str)?;
+
+    // Delete if any other versions found
+    for entry in mod_glob.filter_map(Result::ok) {
+        println!("Removing {}", entry.to_string_lossy());
+        if entry.is_file() {
+            fs::remove_file(&entry).map_err(|e| RfmpError::RemoveOldVersion(entry.clone(), e))?;
+        } else {
+            eprintln!("Failed to remove {}: not a file", entry.to_string_lossy());
+        }
+    }
+
+    Ok(())
+}
+
+/// Build a gitignore-style matcher out of the user-supplied `--exclude` patterns plus a
+/// `.rfmpignore` file in `source_dir`, if one exists. Supports the same syntax as `.gitignore`:
+/// negation with `!`, directory-only patterns with a trailing `/`, and `**` recursion.
+fn build_exclude_matcher(source_dir: &Path, patterns: &[String]) -> Result<Gitignore, RfmpError> {
+    let mut builder = GitignoreBuilder::new(source_dir);
+
+    let rfmpignore_path = source_dir.join(".rfmpignore");
+    if rfmpignore_path.is_file() {
+        if let Some(err) = builder.add(&rfmpignore_path) {
+            return Err(RfmpError::ExcludePattern(err));
+        }
+    }
+
+    for pattern in patterns {
+        builder
+            .add_line(None, pattern)
+            .map_err(RfmpError::ExcludePattern)?;
+    }
+
+    builder.build().map_err(RfmpError::ExcludePattern)
+}
+
+/// Walkdir iter, filtered, rooted at `source_dir`. Excluded directories are pruned during the
+/// walk rather than filtered out entry-by-entry afterwards.
+fn make_walkdir_iter<'a>(
+    source_dir: &'a Path,
+    zip_file_name: &'a str,
+    matcher: &'a Gitignore,
+) -> impl Iterator<Item = PathBuf> + 'a {
+    WalkDir::new(source_dir)
+        .into_iter()
+        .filter_entry(move |e| !walkdir_filter(source_dir, e, zip_file_name, matcher))
+        .filter_map(|de_res| match de_res {
+            Ok(de) => Some(de.path().to_path_buf()),
+            Err(e) => {
+                eprintln!("Error when walking the directory: {e}");
+                None
+            }
+        })
+        .skip(1)
+}
+
+/// Rayon only allows the global thread pool to be built once per process, so calling this more
+/// than once (e.g. once per setting in `pack --dry-run`) must not panic: later calls just keep
+/// whatever pool is already running.
+fn set_new_thread_pool(threads: usize) {
+    let _ = ThreadPoolBuilder::new().num_threads(threads).build_global();
+}
+
+/// Bail out before writing anything if the mod would need a ZIP64 archive.
+///
+/// `mtzip` (as of the version this crate depends on) exposes no API for ZIP64 extra fields or
+/// end-of-central-directory records, so there's no way to drive it into writing one. Until it
+/// grows ZIP64 support, refuse early with a clear error instead of producing a zip Factorio (and
+/// most other tools) would reject as corrupt.
+fn check_classic_zip_limits(paths: &[PathBuf]) -> Result<(), RfmpError> {
+    // +1: `package_to_writer` adds the root `name_version` directory as its own central-directory
+    // entry before these paths, so the final archive has one more entry than `paths.len()`.
+    let entry_count = paths.len() + 1;
+    let total_size: u64 = paths
+        .iter()
+        .filter(|path| path.is_file())
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+
+    if entry_count > CLASSIC_ZIP_ENTRY_LIMIT {
+        return Err(RfmpError::TooManyEntries(entry_count));
+    }
+
+    if total_size > CLASSIC_ZIP_SIZE_LIMIT {
+        return Err(RfmpError::ArchiveTooLarge(total_size));
+    }
+
+    Ok(())
+}
+
+/// Function to filter all entries we don't want to add to the archive, paths relative to
+/// `source_dir`
+fn walkdir_filter(
+    source_dir: &Path,
+    entry: &DirEntry,
+    zip_file_name: &str,
+    matcher: &Gitignore,
+) -> bool {
+    let relative_path = entry
+        .path()
+        .strip_prefix(source_dir)
+        .unwrap_or(entry.path());
+    let filename = entry.file_name();
+    is_filename_eq(filename, zip_file_name)
+        || is_hidden(relative_path, filename)
+        || matcher
+            .matched(relative_path, entry.file_type().is_dir())
+            .is_ignore()
+}
+
+fn is_filename_eq(filename: &OsStr, rhs: &str) -> bool {
+    filename.to_str().map(|v| v == rhs).unwrap_or(false)
+}
+
+fn is_hidden(path: &Path, filename: &OsStr) -> bool {
+    path != AsRef::<Path>::as_ref("")
+        && filename
+            .to_str()
+            .map(|filename| filename.starts_with('.'))
+            .unwrap_or(false)
+}
+
+/// Builder for packaging a mod source directory into a `name_version.zip` archive.
+///
+/// ```no_run
+/// use rfmp::ModPackager;
+///
+/// ModPackager::new("./my-mod")
+///     .stored(true)
+///     .install()?;
+/// # Ok::<(), rfmp::RfmpError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ModPackager {
+    source_dir: PathBuf,
+    excludes: Vec<String>,
+    level: Option<CompressionLevel>,
+    stored: bool,
+    threads: Option<NonZeroUsize>,
+    install_dir: Option<PathBuf>,
+    keep_old_versions: bool,
+}
+
+impl ModPackager {
+    /// Start building a packager for the mod rooted at `source_dir`.
+    pub fn new(source_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            source_dir: source_dir.into(),
+            excludes: Vec::new(),
+            level: None,
+            stored: false,
+            threads: None,
+            install_dir: None,
+            keep_old_versions: false,
+        }
+    }
+
+    /// Add gitignore-style glob patterns (e.g. `**/*.xcf`, `tmp/`) to exclude from the archive.
+    pub fn exclude(mut self, patterns: impl IntoIterator<Item = String>) -> Self {
+        self.excludes.extend(patterns);
+        self
+    }
+
+    /// Set the compression level to use instead of the default (best compression, 9).
+    pub fn level(mut self, level: CompressionLevel) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Store files without compressing them.
+    pub fn stored(mut self, stored: bool) -> Self {
+        self.stored = stored;
+        self
+    }
+
+    /// Amount of threads to use for compression.
+    pub fn threads(mut self, threads: NonZeroUsize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Install to `install_dir` instead of the default Factorio mods directory.
+    pub fn install_dir(mut self, install_dir: impl Into<PathBuf>) -> Self {
+        self.install_dir = Some(install_dir.into());
+        self
+    }
+
+    /// Do not search for other versions of the mod in the install directory and do not try to
+    /// remove them.
+    pub fn keep_old_versions(mut self, keep_old_versions: bool) -> Self {
+        self.keep_old_versions = keep_old_versions;
+        self
+    }
+
+    /// Parse the `info.json` of the mod being packaged.
+    pub fn info_json(&self) -> Result<InfoJson, RfmpError> {
+        get_info_json(&self.source_dir)
+    }
+
+    /// Package the mod and write the resulting archive to `writer`.
+    pub fn package_to_writer<W: Write>(&self, mut writer: W) -> Result<(), RfmpError> {
+        if let Some(threads) = self.threads.map(NonZeroUsize::get) {
+            set_new_thread_pool(threads);
+        }
+
+        let info_json = self.info_json()?;
+        let mod_name_with_version = info_json.to_string();
+        let zip_file_name = format!("{mod_name_with_version}.zip");
+
+        let matcher = build_exclude_matcher(&self.source_dir, &self.excludes)?;
+        let paths: Vec<PathBuf> =
+            make_walkdir_iter(&self.source_dir, &zip_file_name, &matcher).collect();
+        check_classic_zip_limits(&paths)?;
+
+        let mut zipwriter = ZipArchive::default();
+
+        // Add root dir
+        zipwriter.add_directory(mod_name_with_version.clone(), None);
+
+        let path_prefix = Path::new(&mod_name_with_version);
+
+        // Let the zipping begin!
+        for path in paths {
+            let relative_path = path.strip_prefix(&self.source_dir).unwrap_or(&path);
+            let zip_path = path_prefix.join(relative_path);
+            let zipped_name = zip_path.to_string_lossy();
+
+            if path.is_file() {
+                zipwriter.add_file_from_fs(
+                    path.clone(),
+                    zipped_name.to_string(),
+                    self.level,
+                    self.stored.then_some(CompressionType::Stored),
+                );
+            } else if !relative_path.as_os_str().is_empty() {
+                zipwriter
+                    .add_directory_with_metadata_from_fs(zipped_name.to_string(), path)
+                    .map_err(RfmpError::AddDirectory)?;
+            }
+        }
+
+        zipwriter
+            .write_with_rayon(&mut writer)
+            .map_err(RfmpError::WriteArchive)
+    }
+
+    /// Package the mod and write the resulting archive to `path`, overwriting it if it exists.
+    ///
+    /// The archive is assembled in a temporary file next to `path` and only moved into place
+    /// once packaging succeeds, so a rejected or failed package (e.g. hitting the classic zip
+    /// limits) never leaves a broken or truncated archive at `path`.
+    pub fn package_to_path(&self, path: impl AsRef<Path>) -> Result<(), RfmpError> {
+        let path = path.as_ref();
+        let tmp_path = path.with_file_name(format!(
+            "{}.tmp",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        ));
+
+        let file = File::create(&tmp_path)
+            .map_err(|e| RfmpError::CreateOutputFile(tmp_path.clone(), e))?;
+        if let Err(e) = self.package_to_writer(BufWriter::new(file)) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        fs::rename(&tmp_path, path).map_err(|e| {
+            let _ = fs::remove_file(&tmp_path);
+            RfmpError::FinalizeOutputFile(path.to_path_buf(), e)
+        })
+    }
+
+    /// Package the mod and install it into the configured (or default) Factorio mods directory,
+    /// removing older versions unless [`keep_old_versions`](Self::keep_old_versions) was set.
+    /// Returns the path of the installed archive.
+    pub fn install(&self) -> Result<PathBuf, RfmpError> {
+        let mods_target_dir = get_target_dir(self.install_dir.clone())?;
+        let info_json = self.info_json()?;
+
+        if !self.keep_old_versions {
+            remove_old_versions(&mods_target_dir, &info_json.name)?;
+        }
+
+        let zip_file_name = format!("{info_json}.zip");
+        let target_zip_file = mods_target_dir.join(&zip_file_name);
+
+        // As testing found out, removing the file beforehand speeds up the whole process
+        if target_zip_file.exists() {
+            println!("{} exists, removing.", target_zip_file.to_string_lossy());
+            if target_zip_file.is_file() {
+                fs::remove_file(&target_zip_file)
+                    .map_err(|e| RfmpError::RemoveExisting(target_zip_file.clone(), e))?;
+            } else if target_zip_file.is_dir() {
+                // Is this even possible?
+                fs::remove_dir(&target_zip_file)
+                    .map_err(|e| RfmpError::RemoveExisting(target_zip_file.clone(), e))?;
+            }
+        }
+
+        self.package_to_path(&target_zip_file)?;
+
+        Ok(target_zip_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_classic_zip_limits_accounts_for_root_directory_entry() {
+        // The walk yields exactly CLASSIC_ZIP_ENTRY_LIMIT paths, but `package_to_writer` adds one
+        // more entry for the root `name_version` directory, so this must already be rejected.
+        let paths = vec![PathBuf::from("nonexistent"); CLASSIC_ZIP_ENTRY_LIMIT];
+        assert!(matches!(
+            check_classic_zip_limits(&paths),
+            Err(RfmpError::TooManyEntries(n)) if n == CLASSIC_ZIP_ENTRY_LIMIT + 1
+        ));
+    }
+
+    #[test]
+    fn check_classic_zip_limits_allows_exactly_the_limit() {
+        let paths = vec![PathBuf::from("nonexistent"); CLASSIC_ZIP_ENTRY_LIMIT - 1];
+        assert!(check_classic_zip_limits(&paths).is_ok());
+    }
+}
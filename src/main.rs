@@ -1,23 +1,68 @@
 use std::{
-    ffi::OsStr,
-    fmt::Display,
     fs::{self, File},
-    io::BufWriter,
+    io::{self, BufReader, Read, Seek, Write},
     num::NonZeroUsize,
     path::{Path, PathBuf},
+    time::Instant,
 };
 
-use clap::{builder::TypedValueParser, Parser};
-use glob::glob;
-use mtzip::{level::CompressionLevel, CompressionType, ZipArchive};
-use rayon::ThreadPoolBuilder;
-use serde::Deserialize;
+use clap::{builder::TypedValueParser, Parser, Subcommand};
+use mtzip::level::CompressionLevel;
+use rfmp::{InfoJson, ModPackager};
 use serde_json::from_reader;
-use walkdir::{DirEntry, WalkDir};
+use thiserror::Error;
+
+/// Everything that can go wrong in `unpack`/`verify`, which don't go through `rfmp::RfmpError`
+/// since they only read zips rather than package mods.
+#[derive(Debug, Error)]
+enum CliError {
+    #[error("failed to open archive {0}: {1}")]
+    OpenArchive(PathBuf, #[source] std::io::Error),
+    #[error("failed to read archive {0}: {1}")]
+    ReadArchive(PathBuf, #[source] zip::result::ZipError),
+    #[error("archive {0} has no top-level `name_version` directory")]
+    MissingRoot(PathBuf),
+    #[error("failed to read entry {0} of archive: {1}")]
+    ReadEntry(usize, #[source] zip::result::ZipError),
+    #[error("archive entry has an invalid path")]
+    InvalidEntryPath,
+    #[error("failed to create directory {0}: {1}")]
+    CreateDir(PathBuf, #[source] std::io::Error),
+    #[error("failed to create file {0}: {1}")]
+    CreateFile(PathBuf, #[source] std::io::Error),
+    #[error("failed to write file {0}: {1}")]
+    WriteFile(PathBuf, #[source] std::io::Error),
+    #[error("archive {0} does not contain info.json")]
+    MissingInfoJson(PathBuf),
+    #[error("failed to parse info.json in archive {0}: {1}")]
+    ParseInfoJson(PathBuf, #[source] serde_json::Error),
+    #[error("archive root directory `{0}` does not match info.json ({1})")]
+    RootMismatch(String, String),
+    #[error("archive contains a stray entry outside the top-level `{0}` directory: {1}")]
+    StrayEntry(String, String),
+    #[error("archive contains a hidden file or directory: {0}")]
+    HiddenEntry(String),
+}
 
 #[derive(Debug, Clone, Parser)]
 #[clap(author, version, about, long_about = None)]
 struct CliArgs {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Package a mod directory into a `name_version.zip` archive. (default)
+    Pack(PackArgs),
+    /// Extract a previously packaged mod zip back into a directory.
+    Unpack(UnpackArgs),
+    /// Check that a mod zip has the archive layout Factorio expects.
+    Verify(VerifyArgs),
+}
+
+#[derive(Debug, Clone, Parser)]
+struct PackArgs {
     /// Install mod to <PATH> instead of default path.
     ///
     /// Default path is `$HOME/.factorio/mods` on linux and
@@ -30,9 +75,13 @@ struct CliArgs {
     #[clap(short, long, alias = "no-clean")]
     keep_old_versions: bool,
 
-    /// Exclude files or directories from being included in teh archive
-    #[clap(short, long, value_name = "PATH")]
-    exclude: Vec<PathBuf>,
+    /// Exclude files or directories from being included in the archive.
+    ///
+    /// Accepts gitignore-style glob patterns (e.g. `**/*.xcf`, `tmp/`). A `.rfmpignore` file in
+    /// the mod root, using the same syntax as `.gitignore`, is read automatically in addition to
+    /// these.
+    #[clap(short, long, value_name = "PATTERN")]
+    exclude: Vec<String>,
 
     // SAFETY: value range is restricted when clap parses an integer
     /// Set compression level to use instead of default.
@@ -51,204 +100,575 @@ struct CliArgs {
     /// Amount of threads that will be used for compression.
     #[clap(short, long)]
     threads: Option<NonZeroUsize>,
+
+    /// Benchmark compression settings instead of packaging.
+    ///
+    /// Packages the mod in memory under stored and level 1/6/9 compression, printing a table of
+    /// the resulting archive size and wall-clock time for each. Doesn't install anything, remove
+    /// old versions, or create any file.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Clone, Parser)]
+struct UnpackArgs {
+    /// Path to the `name_version.zip` archive to unpack.
+    archive: PathBuf,
+
+    /// Directory to extract into instead of `name_version` in the current directory.
+    #[clap(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
 }
 
-#[derive(Deserialize)]
-struct InfoJson {
-    name: String,
-    version: String,
+#[derive(Debug, Clone, Parser)]
+struct VerifyArgs {
+    /// Path to the `name_version.zip` archive to verify.
+    archive: PathBuf,
 }
 
-impl Display for InfoJson {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}_{}", self.name, self.version)
+/// `pack` is the only subcommand that may be omitted from the command line, so insert it when
+/// the user didn't spell out a subcommand themselves.
+fn normalize_args(mut args: Vec<String>) -> Vec<String> {
+    const SUBCOMMANDS: [&str; 3] = ["pack", "unpack", "verify"];
+    const TOP_LEVEL_FLAGS: [&str; 4] = ["-h", "--help", "-V", "--version"];
+
+    let has_subcommand = args
+        .get(1)
+        .map(|arg| SUBCOMMANDS.contains(&arg.as_str()))
+        .unwrap_or(false);
+    let is_top_level_flag = args
+        .get(1)
+        .map(|arg| TOP_LEVEL_FLAGS.contains(&arg.as_str()))
+        .unwrap_or(false);
+
+    if !has_subcommand && !is_top_level_flag {
+        args.insert(1, "pack".to_string());
     }
+
+    args
 }
 
-fn get_default_factorio_home() -> PathBuf {
-    if cfg!(target_os = "linux") {
-        dirs::home_dir().unwrap().join(".factorio/mods")
-    } else if cfg!(target_os = "windows") {
-        dirs::data_dir().unwrap().join("Factorio/mods")
-    } else {
-        println!("Warning: unknown OS. Please report to github what OS you use and where `mods` directory is located. Using current directory as a fallback");
-        PathBuf::from(".")
+fn main() {
+    let cli_args = CliArgs::parse_from(normalize_args(std::env::args().collect()));
+    #[cfg(debug_assertions)]
+    println!("{cli_args:?}");
+
+    let result = match cli_args.command {
+        Command::Pack(args) => {
+            pack(args);
+            Ok(())
+        }
+        Command::Unpack(args) => unpack(args),
+        Command::Verify(args) => verify(args),
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
     }
 }
 
-/// Open info.json and parse it
-fn get_info_json() -> InfoJson {
-    let info_file = File::open("info.json").expect("info.json not  found");
-    from_reader(info_file).expect("Failed to parse info.json")
+fn pack(args: PackArgs) {
+    pack_in_dir(args, Path::new("."))
 }
 
-/// Mods directory path
-fn get_target_dir(install_dir: Option<PathBuf>) -> PathBuf {
-    let mods_target_dir = install_dir.unwrap_or_else(get_default_factorio_home);
+fn pack_in_dir(args: PackArgs, source_dir: &Path) {
+    if args.dry_run {
+        return dry_run_in_dir(args, source_dir);
+    }
+
+    let PackArgs {
+        install_dir,
+        keep_old_versions,
+        exclude,
+        level,
+        stored,
+        threads,
+        dry_run: _,
+    } = args;
+
+    let mut packager = ModPackager::new(source_dir)
+        .exclude(exclude)
+        .keep_old_versions(keep_old_versions)
+        .stored(stored);
 
-    if !mods_target_dir.exists() {
-        panic!("Error: {} doesn't exist", mods_target_dir.to_string_lossy());
+    if let Some(level) = level {
+        packager = packager.level(level);
+    }
+    if let Some(install_dir) = install_dir {
+        packager = packager.install_dir(install_dir);
+    }
+    if let Some(threads) = threads {
+        packager = packager.threads(threads);
     }
 
-    mods_target_dir
+    match packager.install() {
+        Ok(installed_path) => println!("Packaged mod into {}", installed_path.to_string_lossy()),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+    }
 }
 
-fn make_glob_str(target_dir: &Path, mod_name: &str) -> String {
-    format!(
-        "{}/{}_*[0-9].*[0-9].*[0-9].zip",
-        target_dir.to_string_lossy(),
-        mod_name
-    )
+/// Compression settings benchmarked by `--dry-run`, in the order they're printed.
+const DRY_RUN_SETTINGS: &[(&str, Option<u8>, bool)] = &[
+    ("stored", None, true),
+    ("level 1", Some(1), false),
+    ("level 6", Some(6), false),
+    ("level 9", Some(9), false),
+];
+
+/// A sink that only tallies how many bytes would have been written, so archives can be
+/// benchmarked without touching disk.
+struct CountingSink(u64);
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
-fn remove_old_versions(target_dir: &Path, mod_name: &str) {
-    let mod_glob_str = make_glob_str(target_dir, mod_name);
-    let mod_glob = glob(&mod_glob_str).expect("Failed to construct glob");
+/// Package the mod in memory under several compression settings and print a comparison table,
+/// without installing anything, removing old versions, or creating any file.
+fn dry_run_in_dir(args: PackArgs, source_dir: &Path) {
+    let PackArgs {
+        exclude, threads, ..
+    } = args;
 
-    // Delete if any other versions found
-    for entry in mod_glob.filter_map(Result::ok) {
-        println!("Removing {}", entry.to_string_lossy());
-        if entry.is_file() {
-            fs::remove_file(&entry).expect("Failed to remove file");
-        } else {
-            eprintln!("Failed to remove {}: not a file", entry.to_string_lossy());
+    println!("{:<10} {:>14} {:>12}", "setting", "size (bytes)", "time");
+
+    let mut smallest: Option<(&str, u64)> = None;
+
+    for (label, level, stored) in DRY_RUN_SETTINGS {
+        let mut packager = ModPackager::new(source_dir)
+            .exclude(exclude.clone())
+            .stored(*stored);
+
+        if let Some(level) = level {
+            // SAFETY: benchmark levels are hardcoded and within 0..=9
+            packager = packager.level(unsafe { CompressionLevel::new_unchecked(*level) });
+        }
+        if let Some(threads) = threads {
+            packager = packager.threads(threads);
         }
+
+        let mut sink = CountingSink(0);
+        let start = Instant::now();
+        if let Err(e) = packager.package_to_writer(&mut sink) {
+            eprintln!("Error benchmarking {label}: {e}");
+            std::process::exit(1);
+        }
+        let elapsed = start.elapsed();
+
+        println!("{:<10} {:>14} {:>12?}", label, sink.0, elapsed);
+
+        let is_smallest_so_far = match smallest {
+            Some((_, smallest_size)) => sink.0 < smallest_size,
+            None => true,
+        };
+        if is_smallest_so_far {
+            smallest = Some((label, sink.0));
+        }
+    }
+
+    if let Some((label, size)) = smallest {
+        println!("\nSmallest archive: {label} ({size} bytes)");
     }
 }
 
-/// Walkdir iter, filtered
-fn make_walkdir_iter<'a>(
-    zip_file_name: &'a str,
-    extra_exclude: &'a [PathBuf],
-) -> impl Iterator<Item = PathBuf> + 'a {
-    WalkDir::new(".")
-        .into_iter()
-        .filter_entry(|e| !walkdir_filter(e, zip_file_name, extra_exclude))
-        .filter_map(|de_res| match de_res {
-            Ok(de) => Some(de.path().to_path_buf()),
-            Err(e) => {
-                eprintln!("Error when walking the directory: {e}");
-                None
+/// Extract a `name_version.zip` archive back into a directory, stripping the mandatory
+/// `name_version/` prefix that `pack` adds.
+fn unpack(args: UnpackArgs) -> Result<(), CliError> {
+    let UnpackArgs { archive, output } = args;
+
+    let zip_file = File::open(&archive).map_err(|e| CliError::OpenArchive(archive.clone(), e))?;
+    let mut zip = zip::ZipArchive::new(BufReader::new(zip_file))
+        .map_err(|e| CliError::ReadArchive(archive.clone(), e))?;
+
+    let root_prefix =
+        root_dir_name(&mut zip).ok_or_else(|| CliError::MissingRoot(archive.clone()))?;
+
+    let output_dir = output.unwrap_or_else(|| root_prefix.clone());
+    fs::create_dir_all(&output_dir).map_err(|e| CliError::CreateDir(output_dir.clone(), e))?;
+
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).map_err(|e| CliError::ReadEntry(i, e))?;
+        let entry_path = entry
+            .enclosed_name()
+            .ok_or(CliError::InvalidEntryPath)?
+            .to_path_buf();
+        let relative_path = entry_path.strip_prefix(&root_prefix).unwrap_or(&entry_path);
+
+        if relative_path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let out_path = output_dir.join(relative_path);
+
+        if entry.is_dir() {
+            fs::create_dir_all(&out_path).map_err(|e| CliError::CreateDir(out_path.clone(), e))?;
+        } else {
+            if let Some(parent) = out_path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| CliError::CreateDir(parent.to_path_buf(), e))?;
             }
-        })
-        .skip(1)
+            let mut out_file =
+                File::create(&out_path).map_err(|e| CliError::CreateFile(out_path.clone(), e))?;
+            std::io::copy(&mut entry, &mut out_file)
+                .map_err(|e| CliError::WriteFile(out_path.clone(), e))?;
+        }
+    }
+
+    println!(
+        "Unpacked {} into {}",
+        archive.to_string_lossy(),
+        output_dir.to_string_lossy()
+    );
+
+    Ok(())
 }
 
-fn set_new_thread_pool(threads: usize) {
-    ThreadPoolBuilder::new()
-        .num_threads(threads)
-        .build_global()
-        .unwrap()
+/// Open a mod zip, parse its `info.json` and confirm the archive is laid out the way Factorio
+/// expects: a single `name_version` root directory matching `info.json`, and no stray dotfiles.
+fn verify(args: VerifyArgs) -> Result<(), CliError> {
+    let VerifyArgs { archive } = args;
+
+    let zip_file = File::open(&archive).map_err(|e| CliError::OpenArchive(archive.clone(), e))?;
+    let mut zip = zip::ZipArchive::new(BufReader::new(zip_file))
+        .map_err(|e| CliError::ReadArchive(archive.clone(), e))?;
+
+    let root_prefix =
+        root_dir_name(&mut zip).ok_or_else(|| CliError::MissingRoot(archive.clone()))?;
+
+    let info_json_path = root_prefix.join("info.json");
+    let info_json: InfoJson = {
+        let info_entry = zip
+            .by_name(&info_json_path.to_string_lossy())
+            .map_err(|_| CliError::MissingInfoJson(archive.clone()))?;
+        from_reader(info_entry).map_err(|e| CliError::ParseInfoJson(archive.clone(), e))?
+    };
+
+    let expected_name = info_json.to_string();
+    if root_prefix != Path::new(&expected_name) {
+        return Err(CliError::RootMismatch(
+            root_prefix.to_string_lossy().into_owned(),
+            expected_name,
+        ));
+    }
+
+    for name in zip.file_names() {
+        let entry_path = Path::new(name);
+        let relative_path = entry_path.strip_prefix(&root_prefix).map_err(|_| {
+            CliError::StrayEntry(root_prefix.to_string_lossy().into_owned(), name.to_string())
+        })?;
+
+        let is_hidden_entry = relative_path
+            .components()
+            .filter_map(|c| c.as_os_str().to_str())
+            .any(|c| c.starts_with('.'));
+
+        if is_hidden_entry {
+            return Err(CliError::HiddenEntry(name.to_string()));
+        }
+    }
+
+    println!("{} is a valid mod archive", archive.to_string_lossy());
+
+    Ok(())
 }
 
-fn main() {
-    let cli_args = CliArgs::parse();
-    #[cfg(debug_assertions)]
-    println!("{cli_args:?}");
-    let CliArgs {
-        install_dir,
-        keep_old_versions,
-        exclude,
-        level,
-        stored,
-        threads,
-    } = cli_args;
+/// Name of the single top-level directory every entry in the archive is rooted under, taken from
+/// the first entry in archive order.
+fn root_dir_name<R: Read + Seek>(zip: &mut zip::ZipArchive<R>) -> Option<PathBuf> {
+    let first_entry = zip.by_index_raw(0).ok()?;
+    Path::new(first_entry.name())
+        .components()
+        .next()
+        .map(|component| PathBuf::from(component.as_os_str()))
+}
 
-    if let Some(threads) = threads.map(NonZeroUsize::get) {
-        set_new_thread_pool(threads);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_file(base_dir: &Path, relative: &Path, contents: &[u8]) {
+        let path = base_dir.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap().write_all(contents).unwrap();
     }
 
-    let mods_target_dir = get_target_dir(install_dir);
+    #[test]
+    fn dry_run_benchmarks_without_writing_or_removing_anything() {
+        let src_dir = tempfile::tempdir().unwrap();
 
-    let info_json = get_info_json();
+        write_file(
+            src_dir.path(),
+            Path::new("info.json"),
+            br#"{"name": "dry-run-test", "version": "1.0.0"}"#,
+        );
+        write_file(
+            src_dir.path(),
+            Path::new("prototypes/entity.lua"),
+            b"-- entity prototype\n",
+        );
 
-    // Get mod name/id and version
-    let mod_name_with_version = info_json.to_string();
+        let install_dir = tempfile::tempdir().unwrap();
+        // A stale archive from a previous version; dry-run must leave it untouched since it
+        // never calls `remove_old_versions` or installs anything.
+        write_file(
+            install_dir.path(),
+            Path::new("dry-run-test_0.9.0.zip"),
+            b"stale archive",
+        );
 
-    // Check for other versions
-    if !keep_old_versions {
-        remove_old_versions(&mods_target_dir, &info_json.name)
+        let args = PackArgs {
+            install_dir: Some(install_dir.path().to_path_buf()),
+            keep_old_versions: false,
+            exclude: Vec::new(),
+            level: None,
+            stored: false,
+            threads: NonZeroUsize::new(2),
+            dry_run: true,
+        };
+
+        // Exercises all four benchmarked settings (stored, level 1/6/9) in one process; this is
+        // also the regression test for the rayon global-pool-built-twice panic.
+        pack_in_dir(args, src_dir.path());
+
+        let stale_archive = install_dir.path().join("dry-run-test_0.9.0.zip");
+        assert!(
+            stale_archive.exists(),
+            "dry-run must not remove old versions"
+        );
+        assert_eq!(
+            fs::read_dir(install_dir.path()).unwrap().count(),
+            1,
+            "dry-run must not write any archive to the install directory"
+        );
     }
 
-    // Mod file name
-    let zip_file_name = format!("{mod_name_with_version}.zip");
-    let target_zip_file = mods_target_dir.join(&zip_file_name);
+    #[test]
+    fn pack_unpack_roundtrip() {
+        let src_dir = tempfile::tempdir().unwrap();
+
+        write_file(
+            src_dir.path(),
+            Path::new("info.json"),
+            br#"{"name": "roundtrip-test", "version": "1.0.0"}"#,
+        );
+        write_file(
+            src_dir.path(),
+            Path::new("prototypes/entity.lua"),
+            b"-- entity prototype\n",
+        );
+        write_file(
+            src_dir.path(),
+            Path::new("locale/en/locale.cfg"),
+            b"[mod]\nname=Test\n",
+        );
+
+        let install_dir = tempfile::tempdir().unwrap();
+        ModPackager::new(src_dir.path())
+            .install_dir(install_dir.path())
+            .keep_old_versions(true)
+            .stored(true)
+            .install()
+            .unwrap();
 
-    // As testing found out, removing the file beforehand speeds up the whole process
-    // Delete existing file. This probably wouldn't run unless --no-clean argument is passed.
-    if target_zip_file.exists() {
-        println!("{} exists, removing.", target_zip_file.to_string_lossy());
-        if target_zip_file.is_file() {
-            fs::remove_file(&target_zip_file).expect("Failed to remove file");
-        } else if target_zip_file.is_dir() {
-            // Is this even possible?
-            fs::remove_dir(&target_zip_file).expect("Failed to remove directory");
+        let archive_path = install_dir.path().join("roundtrip-test_1.0.0.zip");
+        assert!(archive_path.exists());
+
+        verify(VerifyArgs {
+            archive: archive_path.clone(),
+        })
+        .unwrap();
+
+        let output_dir = tempfile::tempdir().unwrap();
+        unpack(UnpackArgs {
+            archive: archive_path,
+            output: Some(output_dir.path().to_path_buf()),
+        })
+        .unwrap();
+
+        for relative in ["info.json", "prototypes/entity.lua", "locale/en/locale.cfg"] {
+            let original = fs::read(src_dir.path().join(relative)).unwrap();
+            let roundtripped = fs::read(output_dir.path().join(relative)).unwrap();
+            assert_eq!(original, roundtripped, "mismatch for {relative}");
         }
     }
 
-    // Create archive
-    let mut zipwriter = ZipArchive::default();
+    #[test]
+    fn pack_unpack_roundtrip_excludes_matching_files() {
+        let src_dir = tempfile::tempdir().unwrap();
+
+        write_file(
+            src_dir.path(),
+            Path::new("info.json"),
+            br#"{"name": "exclude-test", "version": "1.0.0"}"#,
+        );
+        write_file(
+            src_dir.path(),
+            Path::new("prototypes/entity.lua"),
+            b"-- entity prototype\n",
+        );
+        write_file(
+            src_dir.path(),
+            Path::new("scratch/notes.txt"),
+            b"don't ship this\n",
+        );
 
-    // Add root dir
-    //println!("Adding root dir");
-    zipwriter.add_directory(mod_name_with_version.clone(), None);
+        let install_dir = tempfile::tempdir().unwrap();
+        ModPackager::new(src_dir.path())
+            .install_dir(install_dir.path())
+            .keep_old_versions(true)
+            .stored(true)
+            .exclude(["scratch/".to_string()])
+            .install()
+            .unwrap();
+
+        let archive_path = install_dir.path().join("exclude-test_1.0.0.zip");
+        let output_dir = tempfile::tempdir().unwrap();
+        unpack(UnpackArgs {
+            archive: archive_path,
+            output: Some(output_dir.path().to_path_buf()),
+        })
+        .unwrap();
 
-    let path_prefix = Path::new(&mod_name_with_version);
+        for relative in ["info.json", "prototypes/entity.lua"] {
+            let original = fs::read(src_dir.path().join(relative)).unwrap();
+            let roundtripped = fs::read(output_dir.path().join(relative)).unwrap();
+            assert_eq!(original, roundtripped, "mismatch for {relative}");
+        }
+        assert!(
+            !output_dir.path().join("scratch/notes.txt").exists(),
+            "excluded file should not have been packaged"
+        );
+    }
 
-    let walkdir = make_walkdir_iter(&zip_file_name, &exclude);
+    #[test]
+    fn pack_respects_rfmpignore_negation_and_cli_excludes() {
+        let src_dir = tempfile::tempdir().unwrap();
 
-    // Let the zipping begin!
-    for path in walkdir {
-        let zip_path = path_prefix.join(
-            path.strip_prefix("./")
-                .expect("Failed to strip './' prefix"),
+        write_file(
+            src_dir.path(),
+            Path::new("info.json"),
+            br#"{"name": "rfmpignore-test", "version": "1.0.0"}"#,
+        );
+        write_file(src_dir.path(), Path::new("keep.txt"), b"keep this\n");
+        write_file(
+            src_dir.path(),
+            Path::new("temp.log"),
+            b"excluded via --exclude\n",
+        );
+        write_file(
+            src_dir.path(),
+            Path::new("build/output.bin"),
+            b"excluded via .rfmpignore\n",
+        );
+        write_file(
+            src_dir.path(),
+            Path::new("build/important.keep"),
+            b"kept via .rfmpignore negation\n",
         );
-        let zipped_name = zip_path.to_string_lossy();
+        // `build/*` (not `build/`) so the directory is still walked, letting the negation below
+        // re-include one file from it, matching real gitignore semantics.
+        write_file(
+            src_dir.path(),
+            Path::new(".rfmpignore"),
+            b"build/*\n!build/important.keep\n",
+        );
+
+        let install_dir = tempfile::tempdir().unwrap();
+        ModPackager::new(src_dir.path())
+            .install_dir(install_dir.path())
+            .keep_old_versions(true)
+            .stored(true)
+            .exclude(["*.log".to_string()])
+            .install()
+            .unwrap();
+
+        let archive_path = install_dir.path().join("rfmpignore-test_1.0.0.zip");
+        let output_dir = tempfile::tempdir().unwrap();
+        unpack(UnpackArgs {
+            archive: archive_path,
+            output: Some(output_dir.path().to_path_buf()),
+        })
+        .unwrap();
 
-        if path.is_file() {
-            //println!("adding file {:?}", zipped_name);
-            zipwriter.add_file_from_fs(
-                path,
-                zipped_name.to_string(),
-                level,
-                stored.then_some(CompressionType::Stored),
-            );
-        } else if !path.as_os_str().is_empty() {
-            //println!("adding dir  {:?}", zipped_name);
-            zipwriter
-                .add_directory_with_metadata_from_fs(zipped_name.to_string(), path)
-                .unwrap();
+        for relative in ["info.json", "keep.txt", "build/important.keep"] {
+            let original = fs::read(src_dir.path().join(relative)).unwrap();
+            let roundtripped = fs::read(output_dir.path().join(relative)).unwrap();
+            assert_eq!(original, roundtripped, "mismatch for {relative}");
         }
+        assert!(
+            !output_dir.path().join("temp.log").exists(),
+            "--exclude pattern should have dropped temp.log"
+        );
+        assert!(
+            !output_dir.path().join("build/output.bin").exists(),
+            ".rfmpignore should have dropped build/output.bin"
+        );
     }
 
-    // Create mod file
-    let mut zip_file =
-        BufWriter::new(File::create(target_zip_file).expect("Failed to open output file"));
+    #[test]
+    fn verify_rejects_stray_top_level_entries() {
+        let archive_dir = tempfile::tempdir().unwrap();
+        let archive_path = archive_dir.path().join("stray-test_1.0.0.zip");
 
-    zipwriter.write_with_rayon(&mut zip_file).unwrap();
-}
+        let file = File::create(&archive_path).unwrap();
+        let mut writer = zip::ZipWriter::new(file);
+        let options = zip::write::FileOptions::default();
 
-/// Function to filter all files we don't want to add to archive
-fn walkdir_filter(entry: &DirEntry, zip_file_name: &str, excludes: &[PathBuf]) -> bool {
-    let entry_path = entry.path();
-    let filename = entry.file_name();
-    is_filename_eq(filename, zip_file_name)
-        || is_hidden(entry_path, filename)
-        || is_in_excludes(entry_path, excludes)
-}
+        writer
+            .start_file("stray-test_1.0.0/info.json", options)
+            .unwrap();
+        writer
+            .write_all(br#"{"name": "stray-test", "version": "1.0.0"}"#)
+            .unwrap();
 
-fn is_filename_eq(filename: &OsStr, rhs: &str) -> bool {
-    filename.to_str().map(|v| v == rhs).unwrap_or(false)
-}
+        writer.start_file("outside.txt", options).unwrap();
+        writer.write_all(b"sneaky").unwrap();
 
-fn is_hidden(path: &Path, filename: &OsStr) -> bool {
-    path != AsRef::<Path>::as_ref(&".")
-        && filename
-            .to_str()
-            .map(|filename| filename.starts_with('.'))
-            .unwrap_or(false)
-}
+        writer.finish().unwrap();
+
+        let result = verify(VerifyArgs {
+            archive: archive_path,
+        });
+        assert!(matches!(result, Err(CliError::StrayEntry(_, _))));
+    }
+
+    #[test]
+    fn pack_rejects_archive_over_classic_zip_size_limit() {
+        let src_dir = tempfile::tempdir().unwrap();
 
-fn is_in_excludes(path: &Path, excludes: &[PathBuf]) -> bool {
-    excludes.iter().any(|e| path.starts_with(e))
+        write_file(
+            src_dir.path(),
+            Path::new("info.json"),
+            br#"{"name": "zip64-test", "version": "1.0.0"}"#,
+        );
+
+        // A sparse file: its reported length exceeds the classic zip 4 GiB limit without
+        // actually consuming that much disk space.
+        File::create(src_dir.path().join("big.dat"))
+            .unwrap()
+            .set_len(u32::MAX as u64 + 1)
+            .unwrap();
+
+        let install_dir = tempfile::tempdir().unwrap();
+        let result = ModPackager::new(src_dir.path())
+            .install_dir(install_dir.path())
+            .keep_old_versions(true)
+            .stored(true)
+            .install();
+
+        assert!(result.is_err(), "pack should refuse a >4 GiB archive");
+        assert!(!install_dir.path().join("zip64-test_1.0.0.zip").exists());
+    }
 }